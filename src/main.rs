@@ -8,18 +8,17 @@ async fn handle_event(qemu: &mut qemu::Process, event: &JsonValue) -> anyhow::Re
     let name = event["event"].as_str().unwrap();
     debug!("Qemu: event {}", name);
 
-    match name {
-        "VNC_INITIALIZED" => {
-            let res = qemu
-                .write(json::object! { "execute": "query-status" })
-                .await;
-
-            if let Ok(Some("prelaunch")) = res.as_ref().map(|data| data["status"].as_str()) {
-                // Start the machine
-                qemu.write(json::object! { "execute": "cont" }).await?;
-            }
-        }
-        _ => (),
+    Ok(())
+}
+
+async fn handle_vnc_initialized(qemu: &mut qemu::Process) -> anyhow::Result<()> {
+    let res = qemu
+        .write(json::object! { "execute": "query-status" })
+        .await;
+
+    if let Ok(Some("prelaunch")) = res.as_ref().map(|data| data["status"].as_str()) {
+        // Start the machine
+        qemu.write(json::object! { "execute": "cont" }).await?;
     }
 
     Ok(())
@@ -95,10 +94,18 @@ async fn main() -> anyhow::Result<()> {
         sched_setaffinity(Pid::from_raw(pid as libc::pid_t), &cpu_mask)?;
     }
 
+    let mut vnc_initialized = child.subscribe("VNC_INITIALIZED").await?;
+
     loop {
-        let error = match child.read_event().await {
-            Ok(event) => handle_event(&mut child, &event).await.err(),
-            Err(e) => Some(e),
+        use futures::StreamExt;
+        use tokio::select;
+
+        let error = select! {
+            event = child.read_event() => match event {
+                Ok(event) => handle_event(&mut child, &event).await.err(),
+                Err(e) => Some(e),
+            },
+            Some(_) = vnc_initialized.next() => handle_vnc_initialized(&mut child).await.err(),
         };
 
         if let Some(e) = error {
@@ -1,13 +1,26 @@
 use futures::channel::{mpsc, oneshot};
 use json::JsonValue;
-use std::{error, fmt, process::ExitStatus};
+use std::{
+    collections::HashMap,
+    error, fmt,
+    path::Path,
+    process::ExitStatus,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
 use tokio::{
-    io::BufReader,
-    process::{Child, ChildStdin, ChildStdout},
+    io::{AsyncBufRead, AsyncWrite, BufReader},
+    process::Child,
     select,
     task::JoinHandle,
 };
 
+type DynRead = Box<dyn AsyncBufRead + Send + Unpin>;
+type DynWrite = Box<dyn AsyncWrite + Send + Unpin>;
+
 pub struct Version(u8, u8, u8);
 
 impl Version {
@@ -42,17 +55,104 @@ impl fmt::Display for Eof {
 
 impl error::Error for Eof {}
 
+pub struct QmpError {
+    pub class: String,
+    pub desc: String,
+}
+
+impl QmpError {
+    fn from_json(json: &JsonValue) -> QmpError {
+        QmpError {
+            class: json["class"].as_str().unwrap().to_owned(),
+            desc: json["desc"].as_str().unwrap().to_owned(),
+        }
+    }
+}
+
+impl fmt::Debug for QmpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "QMP error ({}): {}", self.class, self.desc)
+    }
+}
+
+impl fmt::Display for QmpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (self as &dyn fmt::Debug).fmt(f)
+    }
+}
+
+impl error::Error for QmpError {}
+
+pub struct Timeout;
+
+impl fmt::Debug for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "QMP command timed out")
+    }
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (self as &dyn fmt::Debug).fmt(f)
+    }
+}
+
+impl error::Error for Timeout {}
+
+const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Snapshot of `Process`'s QMP traffic, returned by `Process::stats()`.
+#[derive(Default, Clone, Copy)]
+pub struct QmpStats {
+    pub sent: u64,
+    pub replies: u64,
+    pub errors: u64,
+    pub timeouts: u64,
+    pub last_latency: Duration,
+    latency_sum: Duration,
+}
+
+impl QmpStats {
+    pub fn avg_latency(&self) -> Duration {
+        let completed = self.replies + self.errors;
+        if completed == 0 {
+            Duration::ZERO
+        } else {
+            self.latency_sum / completed as u32
+        }
+    }
+}
+
+type ReplyResult = anyhow::Result<JsonValue>;
+
+/// What the worker owns on the other end of the transport: a child process it
+/// spawned and must reap, or a socket attached to a VM it didn't launch.
+enum Owner {
+    Child(Child),
+    Socket,
+}
+
+/// Outcome of the worker task, mirroring the two `Owner` variants.
+enum WorkerExit {
+    Exited(ExitStatus),
+    Closed,
+}
+
 pub struct Process {
-    worker: JoinHandle<ExitStatus>,
+    worker: JoinHandle<WorkerExit>,
     event_queue: mpsc::Receiver<JsonValue>,
-    reply_queue: mpsc::Sender<(JsonValue, oneshot::Sender<JsonValue>)>,
+    reply_queue: mpsc::Sender<(u64, JsonValue, oneshot::Sender<ReplyResult>)>,
+    sub_queue: mpsc::Sender<(String, mpsc::Sender<JsonValue>)>,
+    cancel_queue: mpsc::Sender<u64>,
+    next_id: AtomicU64,
+    command_timeout_ms: AtomicU64,
+    stats: Mutex<QmpStats>,
 }
 
 impl Process {
     pub async fn init(args: &[String]) -> anyhow::Result<Process> {
-        use log::{debug, trace};
         use std::process::Stdio;
-        use tokio::{io::AsyncBufReadExt, process::Command, task};
+        use tokio::process::Command;
 
         let mut child = Command::new("qemu-system-x86_64")
             .args(args)
@@ -60,13 +160,38 @@ impl Process {
             .stdout(Stdio::piped())
             .spawn()?;
 
-        let stdin = child.stdin.take().unwrap();
-        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let stdin: DynWrite = Box::new(child.stdin.take().unwrap());
+        let stdout: DynRead = Box::new(BufReader::new(child.stdout.take().unwrap()));
+
+        Process::handshake(Owner::Child(child), stdin, stdout).await
+    }
+
+    pub async fn connect(path: &Path) -> anyhow::Result<Process> {
+        use tokio::net::UnixStream;
+
+        let stream = UnixStream::connect(path).await?;
+        let (read_half, write_half) = stream.into_split();
+
+        let stdin: DynWrite = Box::new(write_half);
+        let stdout: DynRead = Box::new(BufReader::new(read_half));
+
+        Process::handshake(Owner::Socket, stdin, stdout).await
+    }
+
+    async fn handshake(
+        owner: Owner,
+        stdin: DynWrite,
+        mut stdout: DynRead,
+    ) -> anyhow::Result<Process> {
+        use log::{debug, trace};
+        use tokio::{io::AsyncBufReadExt, task};
 
         let mut greeting = String::new();
         if stdout.read_line(&mut greeting).await? == 0 {
             trace!("Qemu(pre): Wait");
-            child.wait().await.unwrap();
+            if let Owner::Child(mut child) = owner {
+                child.wait().await.unwrap();
+            }
             return Err(Eof.into());
         }
         trace!("QMP: Recv: {}", greeting.trim());
@@ -77,13 +202,22 @@ impl Process {
 
         let (event_tx, event_rx) = mpsc::channel(1);
         let (reply_tx, reply_rx) = mpsc::channel(1);
+        let (sub_tx, sub_rx) = mpsc::channel(1);
+        let (cancel_tx, cancel_rx) = mpsc::channel(1);
 
-        let worker = task::spawn(qemu_worker(event_tx, reply_rx, child, stdin, stdout));
+        let worker = task::spawn(qemu_worker(
+            event_tx, reply_rx, sub_rx, cancel_rx, owner, stdin, stdout,
+        ));
 
         let mut p = Process {
             worker,
             event_queue: event_rx,
             reply_queue: reply_tx,
+            sub_queue: sub_tx,
+            cancel_queue: cancel_tx,
+            next_id: AtomicU64::new(1),
+            command_timeout_ms: AtomicU64::new(DEFAULT_COMMAND_TIMEOUT.as_millis() as u64),
+            stats: Mutex::new(QmpStats::default()),
         };
 
         match p
@@ -91,28 +225,89 @@ impl Process {
             .await
         {
             Ok(_) => (),
-            Err(e) if e.is::<Eof>() => {
+            Err(e) => {
                 p.finish().await;
-                return Err(e.into());
+                return Err(e);
             }
-            Err(e) => return Err(e.into()),
         }
 
         Ok(p)
     }
 
-    pub async fn write(&mut self, data: JsonValue) -> anyhow::Result<JsonValue> {
+    /// Sends `data` and awaits its matching reply. Commands are correlated by
+    /// an incrementing id, so multiple callers may have a `write` in flight at
+    /// once (e.g. via `tokio::join!`) without serializing on this `&self`.
+    pub async fn write(&self, mut data: JsonValue) -> anyhow::Result<JsonValue> {
         use futures::SinkExt;
+        use log::warn;
+        use std::time::Instant;
+        use tokio::time::timeout;
 
-        let (tx, rx) = oneshot::channel();
-        self.reply_queue.send((data, tx)).await?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        data["id"] = id.into();
 
-        match rx.await {
-            Ok(reply) => Ok(reply),
-            Err(_) => Err(Eof.into()),
+        let (tx, rx) = oneshot::channel();
+        self.reply_queue.clone().send((id, data, tx)).await?;
+
+        self.stats.lock().unwrap().sent += 1;
+        let start = Instant::now();
+        let command_timeout = self.command_timeout();
+
+        match timeout(command_timeout, rx).await {
+            Ok(Ok(reply)) => {
+                let latency = start.elapsed();
+                let mut stats = self.stats.lock().unwrap();
+                stats.last_latency = latency;
+                stats.latency_sum += latency;
+                match reply {
+                    Ok(value) => {
+                        stats.replies += 1;
+                        Ok(value)
+                    }
+                    Err(e) => {
+                        stats.errors += 1;
+                        Err(e)
+                    }
+                }
+            }
+            Ok(Err(_)) => Err(Eof.into()),
+            Err(_) => {
+                warn!("QMP: command {} exceeded {:?} deadline", id, command_timeout);
+                self.stats.lock().unwrap().timeouts += 1;
+                self.cancel_queue.clone().send(id).await.ok();
+                Err(Timeout.into())
+            }
         }
     }
 
+    fn command_timeout(&self) -> Duration {
+        Duration::from_millis(self.command_timeout_ms.load(Ordering::Relaxed))
+    }
+
+    /// Overrides the per-command deadline (default 30s) applied by `write`.
+    pub fn set_timeout(&self, timeout: Duration) {
+        self.command_timeout_ms
+            .store(timeout.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> QmpStats {
+        *self.stats.lock().unwrap()
+    }
+
+    pub async fn execute_hmp(&self, command: &str) -> anyhow::Result<String> {
+        let reply = self
+            .write(json::object! {
+                "execute": "human-monitor-command",
+                "arguments": { "command-line": command },
+            })
+            .await?;
+
+        reply
+            .as_str()
+            .map(str::to_owned)
+            .ok_or_else(|| anyhow::anyhow!("human-monitor-command returned a non-string reply"))
+    }
+
     pub async fn read_event(&mut self) -> anyhow::Result<JsonValue> {
         use futures::StreamExt;
 
@@ -122,43 +317,68 @@ impl Process {
         }
     }
 
+    /// Returns a dedicated stream of events whose `"event"` field matches `event_name`,
+    /// leaving every other consumer (including `read_event`'s catch-all) unaffected.
+    pub async fn subscribe(
+        &mut self,
+        event_name: &str,
+    ) -> anyhow::Result<mpsc::Receiver<JsonValue>> {
+        use futures::SinkExt;
+
+        let (tx, rx) = mpsc::channel(1);
+        self.sub_queue.send((event_name.to_owned(), tx)).await?;
+
+        Ok(rx)
+    }
+
     pub async fn finish(self) {
         use log::{error, trace};
         trace!("Qemu: Wait");
 
-        let res = self.worker.await.unwrap();
-        if !res.success() {
-            error!("Qemu: exited, {}", res);
+        match self.worker.await.unwrap() {
+            WorkerExit::Exited(res) if !res.success() => error!("Qemu: exited, {}", res),
+            WorkerExit::Exited(_) => (),
+            WorkerExit::Closed => trace!("Qemu: socket closed"),
         }
     }
 }
 
 async fn qemu_worker(
     mut event_tx: mpsc::Sender<JsonValue>,
-    mut reply_rx: mpsc::Receiver<(JsonValue, oneshot::Sender<JsonValue>)>,
-    mut child: Child,
-    mut stdin: ChildStdin,
-    stdout: BufReader<ChildStdout>,
-) -> ExitStatus {
+    mut reply_rx: mpsc::Receiver<(u64, JsonValue, oneshot::Sender<ReplyResult>)>,
+    mut sub_rx: mpsc::Receiver<(String, mpsc::Sender<JsonValue>)>,
+    mut cancel_rx: mpsc::Receiver<u64>,
+    owner: Owner,
+    mut stdin: DynWrite,
+    stdout: DynRead,
+) -> WorkerExit {
     use futures::{SinkExt, StreamExt};
     use log::{error, trace};
     use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
 
-    let mut reply_waiter = None;
+    let mut waiters: HashMap<u64, oneshot::Sender<ReplyResult>> = HashMap::new();
     let mut lines = stdout.lines();
+    let mut subscribers: HashMap<String, Vec<mpsc::Sender<JsonValue>>> = HashMap::new();
 
     loop {
         select! {
             biased;
 
-            msg = reply_rx.next(), if reply_waiter.is_none() => {
-                let (data, reply_tx) = msg.unwrap();
-                reply_waiter = Some(reply_tx);
+            msg = reply_rx.next() => {
+                let (id, data, reply_tx) = msg.unwrap();
+                waiters.insert(id, reply_tx);
 
                 let reply_buf = data.to_string();
                 trace!("QMP: Send: {}", &reply_buf);
                 stdin.write_all(reply_buf.as_bytes()).await.unwrap();
             }
+            sub = sub_rx.next() => {
+                let (event_name, tx) = sub.unwrap();
+                subscribers.entry(event_name).or_default().push(tx);
+            }
+            id = cancel_rx.next() => {
+                waiters.remove(&id.unwrap());
+            }
             read = lines.next_line() => {
                 let json = match read {
                     Ok(None) => break,
@@ -170,17 +390,46 @@ async fn qemu_worker(
                 let data = json::parse(&json).unwrap();
 
                 if data.has_key("return") {
-                    if let Some(waiter) = reply_waiter.take() {
-                        waiter.send(data["return"].clone()).unwrap();
-                    } else {
-                        error!("Message reply without waiter");
+                    match data["id"].as_u64().and_then(|id| waiters.remove(&id)) {
+                        Some(waiter) => {
+                            waiter.send(Ok(data["return"].clone())).ok();
+                        }
+                        None => error!("Message reply without waiter"),
+                    }
+                } else if data.has_key("error") {
+                    match data["id"].as_u64().and_then(|id| waiters.remove(&id)) {
+                        Some(waiter) => {
+                            waiter
+                                .send(Err(QmpError::from_json(&data["error"]).into()))
+                                .ok();
+                        }
+                        None => error!("Message error without waiter"),
                     }
                 } else {
-                    event_tx.send(data).await.unwrap();
+                    if let Some(name) = data["event"].as_str() {
+                        if let Some(subs) = subscribers.get_mut(name) {
+                            let mut i = 0;
+                            while i < subs.len() {
+                                if subs[i].send(data.clone()).await.is_err() {
+                                    subs.remove(i);
+                                } else {
+                                    i += 1;
+                                }
+                            }
+                        }
+                    }
+                    event_tx.send(data).await.ok();
                 }
             }
         };
     }
 
-    child.wait().await.unwrap()
+    for (_, waiter) in waiters.drain() {
+        waiter.send(Err(Eof.into())).ok();
+    }
+
+    match owner {
+        Owner::Child(mut child) => WorkerExit::Exited(child.wait().await.unwrap()),
+        Owner::Socket => WorkerExit::Closed,
+    }
 }